@@ -16,6 +16,14 @@
 use kernel::prelude::*;
 use kernel::input::*;
 use kernel::module_param::*;
+use kernel::sync::SpinLock;
+use kernel::time::{Ktime, Timer};
+
+// The button/layer parsing, modifier tracking and scheduled-release queue
+// live in `remap-core`, shared with the userspace fallback daemon in
+// `userspace-daemon`. This module only supplies the kernel-specific
+// `VirtualKeyboard` adapter and the input-handler plumbing around it.
+use remap_core::{DeviceSelector, Disposition, Key as CoreKey, RemapEngine, VirtualKeyboard};
 
 /// Module metadata
 module! {
@@ -29,7 +37,52 @@ module! {
 
 /// Module parameters
 static DEBUG_MODE: ModuleParam<bool> = ModuleParam::new(false, "debug_mode", "Enable debug output");
-static REMAP_SIDE_BUTTONS: ModuleParam<bool> = ModuleParam::new(true, "remap_side_buttons", "Remap side buttons");
+
+/// Comma-separated `SRC_BUTTON=MOD1+MOD2+...+KEY` pairs, e.g.
+/// `"BTN_SIDE=LEFTMETA+PAGEDOWN,BTN_EXTRA=LEFTMETA+PAGEUP"`. Parsed once at
+/// module init (via `remap_core::parse_button_map`) into the engine's
+/// button map; unrecognised names are rejected rather than silently
+/// dropped, so a typo in the param fails module load instead of producing
+/// a half-working remap.
+static BUTTON_MAP: ModuleParam<&'static str> = ModuleParam::new(
+    "BTN_SIDE=LEFTMETA+PAGEDOWN,BTN_EXTRA=LEFTMETA+PAGEUP",
+    "button_map",
+    "Source button to target key chord mapping",
+);
+
+/// Comma-separated `LAYER_BUTTON:SRC_BUTTON=MOD1+...+KEY` triples describing
+/// per-layer overrides, e.g. `"BTN_EXTRA:BTN_SIDE=LEFTCTRL+LEFTALT+RIGHT"`.
+/// `LAYER_BUTTON` becomes a momentary layer shift: while held, `SRC_BUTTON`
+/// resolves through this table instead of `BUTTON_MAP`. Empty by default,
+/// i.e. no layers.
+static LAYER_MAP: ModuleParam<&'static str> = ModuleParam::new(
+    "",
+    "layer_map",
+    "Per-layer source button to target key chord mapping",
+);
+
+/// Comma-separated `SRC_BUTTON/GESTURE=MOD1+...+KEY` triples, `GESTURE` one
+/// of `TAP`/`HOLD`/`DOUBLE`, e.g.
+/// `"BTN_MIDDLE/TAP=LEFTMETA+UP,BTN_MIDDLE/HOLD=LEFTMETA+DOWN"`. A button listed
+/// here is dispatched through [`remap_core`]'s hold/double-tap timing
+/// instead of firing its chord on every press; empty by default, i.e. no
+/// gesture buttons.
+static GESTURE_MAP: ModuleParam<&'static str> = ModuleParam::new(
+    "",
+    "gesture_map",
+    "Per-button tap/hold/double-tap key chord mapping",
+);
+
+/// `VVVV:PPPP` vendor:product hex id, or a `phys`-style physical-location
+/// string matched against [`InputDevice::phys`]. Empty (the default) falls
+/// back to the name/capability heuristic in
+/// [`M720Remapper::is_m720_device`], which is ambiguous once more than one
+/// Logitech device is plugged in.
+static DEVICE_SELECTOR: ModuleParam<&'static str> = ModuleParam::new(
+    "",
+    "device_selector",
+    "Pin the target device by vendor:product id or phys location instead of the name heuristic",
+);
 
 /// Device structure
 struct M720Device {
@@ -38,24 +91,123 @@ struct M720Device {
     name: String<64>,
 }
 
+/// Translate a physical key code from the kernel's input layer into
+/// `remap-core`'s backend-agnostic [`CoreKey`]. Returns `None` for codes
+/// `remap-core` doesn't know about, which `handle_event` treats as an
+/// ordinary pass-through key.
+fn from_kernel_key(key: Key) -> Option<CoreKey> {
+    Some(match key {
+        Key::LeftShift => CoreKey::LeftShift,
+        Key::RightShift => CoreKey::RightShift,
+        Key::LeftCtrl => CoreKey::LeftCtrl,
+        Key::RightCtrl => CoreKey::RightCtrl,
+        Key::LeftAlt => CoreKey::LeftAlt,
+        Key::RightAlt => CoreKey::RightAlt,
+        Key::LeftMeta => CoreKey::LeftMeta,
+        Key::RightMeta => CoreKey::RightMeta,
+        Key::PageUp => CoreKey::PageUp,
+        Key::PageDown => CoreKey::PageDown,
+        Key::Left => CoreKey::Left,
+        Key::Right => CoreKey::Right,
+        Key::Up => CoreKey::Up,
+        Key::Down => CoreKey::Down,
+        Key::ButtonSide => CoreKey::ButtonSide,
+        Key::ButtonExtra => CoreKey::ButtonExtra,
+        Key::ButtonMiddle => CoreKey::ButtonMiddle,
+        _ => return None,
+    })
+}
+
+/// The inverse of [`from_kernel_key`], used when the engine emits a key on
+/// the virtual keyboard.
+fn to_kernel_key(key: CoreKey) -> Key {
+    match key {
+        CoreKey::LeftShift => Key::LeftShift,
+        CoreKey::RightShift => Key::RightShift,
+        CoreKey::LeftCtrl => Key::LeftCtrl,
+        CoreKey::RightCtrl => Key::RightCtrl,
+        CoreKey::LeftAlt => Key::LeftAlt,
+        CoreKey::RightAlt => Key::RightAlt,
+        CoreKey::LeftMeta => Key::LeftMeta,
+        CoreKey::RightMeta => Key::RightMeta,
+        CoreKey::PageUp => Key::PageUp,
+        CoreKey::PageDown => Key::PageDown,
+        CoreKey::Left => Key::Left,
+        CoreKey::Right => Key::Right,
+        CoreKey::Up => Key::Up,
+        CoreKey::Down => Key::Down,
+        CoreKey::ButtonSide => Key::ButtonSide,
+        CoreKey::ButtonExtra => Key::ButtonExtra,
+        CoreKey::ButtonMiddle => Key::ButtonMiddle,
+    }
+}
+
+/// The [`VirtualKeyboard`] `remap-core` drives: a thin wrapper around the
+/// kernel's virtual input device that translates key codes on the way out
+/// and otherwise just forwards press/release/sync.
+struct KernelVirtualKeyboard {
+    device: VirtualInputDevice,
+}
+
+impl VirtualKeyboard for KernelVirtualKeyboard {
+    type Error = Error;
+
+    fn press(&mut self, key: CoreKey) -> Result<()> {
+        self.device.send_key_press(to_kernel_key(key))
+    }
+
+    fn release(&mut self, key: CoreKey) -> Result<()> {
+        self.device.send_key_release(to_kernel_key(key))
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.device.sync()
+    }
+}
+
 /// Main module structure
 struct M720Remapper {
     handler: InputHandler,
-    virtual_kbd: VirtualInputDevice,
+    /// Watches every keyboard on the system (unexclusively) purely to feed
+    /// real Shift/Ctrl/Alt/Meta presses into `engine`'s modifier tracking --
+    /// the M720 itself is a mouse and `handler` above never sees one, so
+    /// without this `modifier_state` would sit empty forever and a remapped
+    /// chord could stomp a modifier the user is actually holding.
+    keyboard_handler: InputHandler,
+    /// Button/layer/gesture config, modifier tracking and the
+    /// scheduled-action queue, shared with the userspace daemon via
+    /// `remap-core`.
+    engine: SpinLock<RemapEngine<KernelVirtualKeyboard>>,
+    /// `DEVICE_SELECTOR` parsed once at module load, same as the button,
+    /// layer and gesture maps `engine` holds -- `connect_device` reads this instead of
+    /// re-parsing the param on every hotplug event, so a malformed selector
+    /// fails module load with one clear error instead of failing silently
+    /// and repeatedly per connect callback.
+    device_selector: Option<DeviceSelector>,
+    /// Fires whenever the engine's earliest scheduled action is due.
+    timer: Timer,
 }
 
 impl KernelModule for M720Remapper {
     fn init(_name: &'static CStr, _module: &'static ThisModule) -> Result<Self> {
         pr_info!("Loading M720 Remapper Rust module v1.0.0");
-        
-        // Create virtual keyboard device
-        let virtual_kbd = VirtualInputDevice::new("M720 Virtual Keyboard")?;
-        virtual_kbd.set_capability(InputEventType::Key)?;
-        virtual_kbd.set_key_capability(Key::LeftMeta)?;
-        virtual_kbd.set_key_capability(Key::PageUp)?;
-        virtual_kbd.set_key_capability(Key::PageDown)?;
-        virtual_kbd.register()?;
-        
+
+        let mapping = remap_core::parse_button_map(*BUTTON_MAP).map_err(|_| EINVAL)?;
+        let layers = remap_core::parse_layer_map(*LAYER_MAP).map_err(|_| EINVAL)?;
+        let gestures = remap_core::parse_gesture_map(*GESTURE_MAP).map_err(|_| EINVAL)?;
+        let device_selector = remap_core::parse_device_selector(*DEVICE_SELECTOR).map_err(|_| EINVAL)?;
+
+        // Create virtual keyboard device, exposing exactly the keys the
+        // engine might ever emit.
+        let device = VirtualInputDevice::new("M720 Virtual Keyboard")?;
+        device.set_capability(InputEventType::Key)?;
+        let keyboard = KernelVirtualKeyboard { device };
+        let mut engine = RemapEngine::new(keyboard, mapping, layers, gestures);
+        for key in engine.emitted_keys() {
+            engine.backend_mut().device.set_key_capability(to_kernel_key(key))?;
+        }
+        engine.backend_mut().device.register()?;
+
         // Create input handler
         let handler = InputHandler::new(
             "m720_remapper_rust",
@@ -63,14 +215,33 @@ impl KernelModule for M720Remapper {
             Self::disconnect_device,
             Self::handle_event,
         )?;
-        
+
         handler.register()?;
-        
+
+        // A second, separate handler for keyboards: its id_table matches any
+        // EV_KEY device reporting a modifier key, not just the M720, and its
+        // connect callback never rejects based on DEVICE_SELECTOR -- it
+        // exists only to keep modifier_state honest, not to pick out *the*
+        // device the way `handler` above does.
+        let keyboard_handler = InputHandler::new(
+            "m720_remapper_rust_modifiers",
+            Self::connect_keyboard_device,
+            Self::disconnect_device,
+            Self::handle_keyboard_event,
+        )?;
+
+        keyboard_handler.register()?;
+
+        let timer = Timer::new(Self::drain_scheduled_events);
+
         pr_info!("M720 Remapper Rust module loaded successfully");
-        
+
         Ok(M720Remapper {
             handler,
-            virtual_kbd,
+            keyboard_handler,
+            engine: SpinLock::new(engine),
+            device_selector,
+            timer,
         })
     }
 }
@@ -78,8 +249,10 @@ impl KernelModule for M720Remapper {
 impl Drop for M720Remapper {
     fn drop(&mut self) {
         pr_info!("Unloading M720 Remapper Rust module");
+        self.timer.cancel();
         self.handler.unregister();
-        self.virtual_kbd.unregister();
+        self.keyboard_handler.unregister();
+        self.engine.lock().backend_mut().device.unregister();
     }
 }
 
@@ -95,86 +268,121 @@ impl M720Remapper {
         }
         false
     }
-    
+
+    /// True if `dev` is the device `DEVICE_SELECTOR` pins, or (when it's
+    /// empty) the device [`Self::is_m720_device`]'s heuristic picks out.
+    fn matches_selected_device(dev: &InputDevice, selector: &Option<DeviceSelector>) -> bool {
+        match selector {
+            Some(DeviceSelector::VendorProduct { vendor, product }) => {
+                dev.vendor_id() == *vendor && dev.product_id() == *product
+            }
+            Some(DeviceSelector::Path(phys)) => dev.phys().map(|p| p == phys.as_str()).unwrap_or(false),
+            None => Self::is_m720_device(dev),
+        }
+    }
+
     /// Connect to new device
     fn connect_device(handler: &InputHandler, dev: &InputDevice) -> Result<InputHandle> {
-        if !Self::is_m720_device(dev) {
+        let remapper = handler.context();
+        if !Self::matches_selected_device(dev, &remapper.device_selector) {
             return Err(ENODEV);
         }
-        
+
         pr_info!("Connecting to M720 device: {}", dev.name().unwrap_or("Unknown"));
-        
+
         let handle = handler.create_handle(dev)?;
         handle.open()?;
-        
+
         Ok(handle)
     }
-    
+
     /// Disconnect from device
     fn disconnect_device(handle: &InputHandle) {
         pr_info!("Disconnecting from M720 device");
         handle.close();
     }
-    
+
+    /// True for a device that reports at least one physical modifier key --
+    /// good enough to tell a real keyboard apart from the M720 (which has
+    /// none of these) without needing a name match. Mirrors
+    /// `userspace-daemon::device::is_keyboard_device`.
+    fn is_keyboard_device(dev: &InputDevice) -> bool {
+        dev.has_key_capability(Key::LeftShift)
+            || dev.has_key_capability(Key::LeftCtrl)
+            || dev.has_key_capability(Key::LeftAlt)
+            || dev.has_key_capability(Key::LeftMeta)
+    }
+
+    /// Connect to a keyboard for modifier tracking only -- unlike
+    /// `connect_device`, this never rejects based on `DEVICE_SELECTOR`, since
+    /// every keyboard on the system should feed `modifier_state`, not just
+    /// whichever one the selector happens to pin.
+    fn connect_keyboard_device(handler: &InputHandler, dev: &InputDevice) -> Result<InputHandle> {
+        if !Self::is_keyboard_device(dev) {
+            return Err(ENODEV);
+        }
+
+        pr_info!("Watching keyboard for modifier state: {}", dev.name().unwrap_or("Unknown"));
+
+        let handle = handler.create_handle(dev)?;
+        handle.open()?;
+        Ok(handle)
+    }
+
+    /// Handle a keyboard event: mirror modifier presses into the engine's
+    /// tracking and nothing else. The handle is never grabbed, so the kernel
+    /// already delivers this event to its normal destination -- returning
+    /// `PassThrough` here is purely informational, not a forwarding decision.
+    fn handle_keyboard_event(handle: &InputHandle, event: &InputEvent) -> Result<EventResult> {
+        let remapper = handle.context();
+
+        if let InputEvent::Key { code, value, .. } = event {
+            if let Some(core_key) = from_kernel_key(*code) {
+                remapper.engine.lock().note_modifier(core_key, *value);
+            }
+        }
+
+        Ok(EventResult::PassThrough)
+    }
+
     /// Handle input events
+    ///
+    /// Translates the kernel's key code into `remap-core`'s vocabulary and
+    /// hands it to the shared [`RemapEngine`]; codes it doesn't recognise
+    /// (or a [`Disposition::PassThrough`] verdict) fall through untouched.
     fn handle_event(handle: &InputHandle, event: &InputEvent) -> Result<EventResult> {
-        match event {
-            InputEvent::Key { code: Key::ButtonSide, value: 1, .. } => {
-                if *REMAP_SIDE_BUTTONS {
-                    Self::send_workspace_down(&handle.context().virtual_kbd)?;
-                    return Ok(EventResult::Consumed);
+        let remapper = handle.context();
+
+        if let InputEvent::Key { code, value, .. } = event {
+            if let Some(core_key) = from_kernel_key(*code) {
+                let now_ms = Ktime::now().to_ms();
+                let mut engine = remapper.engine.lock();
+                let disposition = engine.handle_event(core_key, *value, now_ms)?;
+                // Arm the timer for whatever this event just scheduled (a
+                // chord release or a gesture deadline).
+                if let Some(next_ms) = engine.next_deadline() {
+                    remapper.timer.schedule_at(Ktime::from_ms(next_ms as i64));
                 }
-            }
-            InputEvent::Key { code: Key::ButtonExtra, value: 1, .. } => {
-                if *REMAP_SIDE_BUTTONS {
-                    Self::send_workspace_up(&handle.context().virtual_kbd)?;
+                if disposition == Disposition::Consumed {
                     return Ok(EventResult::Consumed);
                 }
             }
-            _ => {}
         }
-        
+
         Ok(EventResult::PassThrough)
     }
-    
-    /// Send workspace down key combination
-    fn send_workspace_down(virt_kbd: &VirtualInputDevice) -> Result<()> {
-        if *DEBUG_MODE {
-            pr_info!("Sending workspace down: Meta+PageDown");
+
+    /// Timer callback: ask the engine to flush every transition whose
+    /// deadline has elapsed, then re-arm for whatever is left.
+    fn drain_scheduled_events(&self) {
+        let now_ms = Ktime::now().to_ms();
+        let mut engine = self.engine.lock();
+        if let Err(e) = engine.drain(now_ms) {
+            pr_info!("Failed to emit scheduled key event: {:?}", e);
         }
-        
-        virt_kbd.send_key_press(Key::LeftMeta)?;
-        virt_kbd.send_key_press(Key::PageDown)?;
-        virt_kbd.sync()?;
-        
-        // Small delay
-        kernel::delay::msleep(10);
-        
-        virt_kbd.send_key_release(Key::PageDown)?;
-        virt_kbd.send_key_release(Key::LeftMeta)?;
-        virt_kbd.sync()?;
-        
-        Ok(())
-    }
-    
-    /// Send workspace up key combination
-    fn send_workspace_up(virt_kbd: &VirtualInputDevice) -> Result<()> {
-        if *DEBUG_MODE {
-            pr_info!("Sending workspace up: Meta+PageUp");
+        if let Some(next_ms) = engine.next_deadline() {
+            self.timer.schedule_at(Ktime::from_ms(next_ms as i64));
         }
-        
-        virt_kbd.send_key_press(Key::LeftMeta)?;
-        virt_kbd.send_key_press(Key::PageUp)?;
-        virt_kbd.sync()?;
-        
-        // Small delay
-        kernel::delay::msleep(10);
-        
-        virt_kbd.send_key_release(Key::PageUp)?;
-        virt_kbd.send_key_release(Key::LeftMeta)?;
-        virt_kbd.sync()?;
-        
-        Ok(())
     }
 }
 */
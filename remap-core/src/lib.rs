@@ -0,0 +1,1030 @@
+//! Shared remap logic for the M720 button remapper.
+//!
+//! This crate holds everything that doesn't care whether it's driven by a
+//! kernel input handler or a userspace evdev read loop: the key vocabulary,
+//! button->chord, layer and gesture config parsing, device-selector
+//! parsing, modifier-state tracking, and the scheduled-action queue that
+//! lets a chord's release -- or a hold/double-tap decision -- be deferred
+//! without blocking the caller. Device I/O (actually pressing/releasing a
+//! key on a virtual keyboard, or resolving a [`DeviceSelector`] to a real
+//! device handle) is the one thing that differs per backend, so the
+//! keyboard side is kept behind the small [`VirtualKeyboard`] trait and
+//! injected by the caller rather than implemented here.
+//!
+//! `no_std` + `alloc` so the same code can eventually back a real kernel
+//! Rust module as well as the userspace daemon in `userspace-daemon`.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// How far in the future a chord's release is scheduled after its press.
+pub const CHORD_RELEASE_DELAY_MS: u64 = 10;
+
+/// How long a gesture button must stay down before it resolves as a hold
+/// rather than a tap.
+pub const HOLD_THRESHOLD_MS: u64 = 400;
+
+/// How long after a tap's release a second press still counts as a
+/// double-tap rather than a fresh, independent tap.
+pub const DOUBLE_TAP_WINDOW_MS: u64 = 300;
+
+/// The keys this crate knows how to name and remap. Deliberately a small
+/// subset of a full evdev key table -- extend as new remaps are added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Key {
+    LeftShift,
+    RightShift,
+    LeftCtrl,
+    RightCtrl,
+    LeftAlt,
+    RightAlt,
+    LeftMeta,
+    RightMeta,
+    PageUp,
+    PageDown,
+    Left,
+    Right,
+    Up,
+    Down,
+    ButtonSide,
+    ButtonExtra,
+    ButtonMiddle,
+}
+
+impl Key {
+    /// Parse the upper-cased name used in config strings (module params,
+    /// CLI args, config files), e.g. `"LEFTMETA"` or `"BTN_SIDE"`.
+    pub fn from_name(name: &str) -> Result<Self, ParseError> {
+        Ok(match name {
+            "LEFTSHIFT" | "SHIFT" => Key::LeftShift,
+            "RIGHTSHIFT" => Key::RightShift,
+            "LEFTCTRL" | "CTRL" => Key::LeftCtrl,
+            "RIGHTCTRL" => Key::RightCtrl,
+            "LEFTALT" | "ALT" => Key::LeftAlt,
+            "RIGHTALT" => Key::RightAlt,
+            "LEFTMETA" | "META" | "SUPER" => Key::LeftMeta,
+            "RIGHTMETA" => Key::RightMeta,
+            "PAGEUP" => Key::PageUp,
+            "PAGEDOWN" => Key::PageDown,
+            "LEFT" => Key::Left,
+            "RIGHT" => Key::Right,
+            "UP" => Key::Up,
+            "DOWN" => Key::Down,
+            "BTN_SIDE" => Key::ButtonSide,
+            "BTN_EXTRA" => Key::ButtonExtra,
+            "BTN_MIDDLE" => Key::ButtonMiddle,
+            other => return Err(ParseError::UnknownKeyName(Buf::from(other))),
+        })
+    }
+
+    /// True for keys whose physical, user-held state [`ModifierState`]
+    /// tracks.
+    pub fn is_modifier(self) -> bool {
+        matches!(
+            self,
+            Key::LeftShift
+                | Key::RightShift
+                | Key::LeftCtrl
+                | Key::RightCtrl
+                | Key::LeftAlt
+                | Key::RightAlt
+                | Key::LeftMeta
+                | Key::RightMeta
+        )
+    }
+}
+
+/// A short owned copy of an unrecognised token, kept around purely so
+/// [`ParseError`] can report it without requiring `std::String`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Buf(Vec<u8>);
+
+impl Buf {
+    fn from(s: &str) -> Self {
+        Buf(s.as_bytes().to_vec())
+    }
+}
+
+impl fmt::Display for Buf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", core::str::from_utf8(&self.0).unwrap_or("<invalid>"))
+    }
+}
+
+/// A config string failed to parse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    UnknownKeyName(Buf),
+    MalformedEntry(Buf),
+    EmptyChord(Buf),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnknownKeyName(n) => write!(f, "unknown key name: {n}"),
+            ParseError::MalformedEntry(e) => write!(f, "malformed mapping entry: {e}"),
+            ParseError::EmptyChord(e) => write!(f, "chord has no keys: {e}"),
+        }
+    }
+}
+
+/// Source button -> target chord (modifiers followed by the final key).
+pub type ButtonMap = BTreeMap<Key, Vec<Key>>;
+
+/// Layer-shift button -> its override button map.
+pub type LayerMap = BTreeMap<Key, ButtonMap>;
+
+/// Parse a `SRC=MOD1+MOD2+...+KEY` comma-separated list into a [`ButtonMap`],
+/// e.g. `"BTN_SIDE=LEFTMETA+PAGEDOWN,BTN_EXTRA=LEFTMETA+PAGEUP"`.
+pub fn parse_button_map(spec: &str) -> Result<ButtonMap, ParseError> {
+    let mut mapping = BTreeMap::new();
+    for entry in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (src, chord) = entry
+            .split_once('=')
+            .ok_or_else(|| ParseError::MalformedEntry(Buf::from(entry)))?;
+        mapping.insert(Key::from_name(src.trim())?, parse_chord(chord, entry)?);
+    }
+    Ok(mapping)
+}
+
+/// Parse a `LAYER:SRC=MOD1+...+KEY` comma-separated list into a [`LayerMap`].
+pub fn parse_layer_map(spec: &str) -> Result<LayerMap, ParseError> {
+    let mut layers: LayerMap = BTreeMap::new();
+    for entry in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (layer_name, rest) = entry
+            .split_once(':')
+            .ok_or_else(|| ParseError::MalformedEntry(Buf::from(entry)))?;
+        let (src, chord) = rest
+            .split_once('=')
+            .ok_or_else(|| ParseError::MalformedEntry(Buf::from(entry)))?;
+
+        let layer_key = Key::from_name(layer_name.trim())?;
+        let src_key = Key::from_name(src.trim())?;
+        let keys = parse_chord(chord, entry)?;
+
+        layers.entry(layer_key).or_default().insert(src_key, keys);
+    }
+    Ok(layers)
+}
+
+/// Every key code a [`ButtonMap`]/[`LayerMap`]/[`GestureMap`] combination
+/// might ever emit, so a caller can register virtual-device key
+/// capabilities before the engine (and the [`VirtualKeyboard`] backend it
+/// needs) exist yet.
+pub fn emitted_keys(mapping: &ButtonMap, layers: &LayerMap, gestures: &GestureMap) -> Vec<Key> {
+    let mut keys: Vec<Key> = mapping.values().flatten().copied().collect();
+    keys.extend(layers.values().flat_map(|table| table.values().flatten().copied()));
+    keys.extend(gestures.values().flat_map(|chords| {
+        [&chords.tap, &chords.hold, &chords.double_tap]
+            .into_iter()
+            .flatten()
+            .flatten()
+            .copied()
+    }));
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+fn parse_chord(chord: &str, entry: &str) -> Result<Vec<Key>, ParseError> {
+    let keys: Vec<Key> = chord
+        .split('+')
+        .map(|name| Key::from_name(name.trim()))
+        .collect::<Result<_, _>>()?;
+    if keys.is_empty() {
+        return Err(ParseError::EmptyChord(Buf::from(entry)));
+    }
+    Ok(keys)
+}
+
+/// The up-to-three chords a gesture button may resolve to, keyed by how it
+/// was pressed. Any of the three may be absent, in which case that gesture
+/// on this button is simply not remapped (and falls through as whatever the
+/// caller does with [`Disposition::PassThrough`]... except gesture buttons
+/// are always [`Disposition::Consumed`], so an absent chord just means
+/// nothing is emitted for that gesture).
+#[derive(Debug, Clone, Default)]
+pub struct GestureChords {
+    pub tap: Option<Vec<Key>>,
+    pub hold: Option<Vec<Key>>,
+    pub double_tap: Option<Vec<Key>>,
+}
+
+/// Gesture button -> its tap/hold/double-tap chords.
+pub type GestureMap = BTreeMap<Key, GestureChords>;
+
+/// Parse a `SRC/GESTURE=MOD1+...+KEY` comma-separated list into a
+/// [`GestureMap`], e.g. `"BTN_MIDDLE/TAP=LEFTMETA+UP,BTN_MIDDLE/HOLD=LEFTMETA+DOWN"`.
+/// `GESTURE` is one of `TAP`, `HOLD`, `DOUBLE`.
+pub fn parse_gesture_map(spec: &str) -> Result<GestureMap, ParseError> {
+    let mut gestures: GestureMap = BTreeMap::new();
+    for entry in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (src, rest) = entry
+            .split_once('/')
+            .ok_or_else(|| ParseError::MalformedEntry(Buf::from(entry)))?;
+        let (gesture, chord) = rest
+            .split_once('=')
+            .ok_or_else(|| ParseError::MalformedEntry(Buf::from(entry)))?;
+
+        let src_key = Key::from_name(src.trim())?;
+        let keys = parse_chord(chord, entry)?;
+        let slot = gestures.entry(src_key).or_default();
+        match gesture.trim() {
+            "TAP" => slot.tap = Some(keys),
+            "HOLD" => slot.hold = Some(keys),
+            "DOUBLE" => slot.double_tap = Some(keys),
+            _ => return Err(ParseError::MalformedEntry(Buf::from(entry))),
+        }
+    }
+    Ok(gestures)
+}
+
+/// Pins the target device deterministically instead of the name/capability
+/// heuristic in `is_m720_device`, which is ambiguous once more than one
+/// Logitech device (or a Unifying receiver juggling several) is plugged in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// USB vendor:product id pair, e.g. parsed from `"046d:b01c"`.
+    VendorProduct { vendor: u16, product: u16 },
+    /// A stable path or location string -- a `/dev/input/by-id/...` or
+    /// `/dev/input/by-path/...` symlink in userspace, a kernel `phys`
+    /// string in the kernel backend -- matched verbatim by the caller,
+    /// since resolving it is backend-specific.
+    Path(String),
+}
+
+/// Parse a `DEVICE_SELECTOR`-style string. Empty means "no pin, fall back
+/// to the name/capability heuristic"; `VVVV:PPPP` (hex vendor:product)
+/// pins by USB id; anything else is taken as a [`DeviceSelector::Path`].
+pub fn parse_device_selector(spec: &str) -> Result<Option<DeviceSelector>, ParseError> {
+    let spec = spec.trim();
+    if spec.is_empty() {
+        return Ok(None);
+    }
+    if let Some((vendor, product)) = spec.split_once(':') {
+        if let (Ok(vendor), Ok(product)) =
+            (u16::from_str_radix(vendor, 16), u16::from_str_radix(product, 16))
+        {
+            return Ok(Some(DeviceSelector::VendorProduct { vendor, product }));
+        }
+    }
+    Ok(Some(DeviceSelector::Path(String::from(spec))))
+}
+
+/// Tracks which modifier keys the user is physically holding down, as
+/// observed from pass-through events. Consulted before emitting a remapped
+/// chord so the chord never presses a modifier that's already down, and
+/// never releases one the user didn't ask to release.
+#[derive(Default)]
+pub struct ModifierState {
+    held: Vec<Key>,
+}
+
+impl ModifierState {
+    pub fn is_held(&self, key: Key) -> bool {
+        self.held.contains(&key)
+    }
+
+    pub fn set(&mut self, key: Key, held: bool) {
+        if held {
+            if !self.held.contains(&key) {
+                self.held.push(key);
+            }
+        } else {
+            self.held.retain(|&k| k != key);
+        }
+    }
+}
+
+/// Tracks which layer-shift buttons are currently held, innermost (most
+/// recently pressed) last, so nested holds resolve to the deepest layer and
+/// unwind correctly as buttons are released out of order.
+#[derive(Default)]
+pub struct LayerManager {
+    stack: Vec<Key>,
+}
+
+impl LayerManager {
+    pub fn push(&mut self, layer_button: Key) {
+        self.stack.push(layer_button);
+    }
+
+    pub fn pop(&mut self, layer_button: Key) {
+        if let Some(pos) = self.stack.iter().rposition(|&k| k == layer_button) {
+            self.stack.remove(pos);
+        }
+    }
+
+    pub fn active(&self) -> Option<Key> {
+        self.stack.last().copied()
+    }
+}
+
+/// A press or release of a single key, as queued inside an
+/// [`ScheduledAction::Emit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyTransition {
+    Press(Key),
+    Release(Key),
+}
+
+/// What a [`ScheduledEvent`] does once its deadline elapses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduledAction {
+    /// Emit a single key press or release on the backend.
+    Emit(KeyTransition),
+    /// Re-check this gesture button's hold threshold or double-tap window.
+    /// Re-checking (rather than unconditionally firing) matters because the
+    /// button's state may have moved on since this was scheduled -- e.g. a
+    /// second tap already resolved the gesture, making this deadline stale.
+    GestureDeadline(Key),
+}
+
+/// A single action waiting to run once `emit_at` (a caller-defined
+/// monotonic millisecond timestamp) elapses.
+pub struct ScheduledEvent {
+    pub action: ScheduledAction,
+    pub emit_at: u64,
+    /// Layer-shift button active when this was scheduled, if any, so
+    /// [`RemapEngine::release_layer`] can reclaim it early.
+    pub layer: Option<Key>,
+}
+
+/// What the caller should do with the input event it fed to
+/// [`RemapEngine::handle_event`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    /// The event belongs to a button this engine remaps (a mapped, layer or
+    /// gesture button), whether or not it emitted anything for this
+    /// particular press/release; the caller must not forward the original
+    /// event.
+    Consumed,
+    /// Not a button this engine remaps; the caller should forward it as-is.
+    PassThrough,
+}
+
+/// A virtual keyboard that can press/release/sync keys. The one thing that
+/// differs between the (future) kernel backend and the userspace
+/// evdev/uinput backend -- everything else in this crate is backend
+/// agnostic.
+pub trait VirtualKeyboard {
+    type Error;
+
+    fn press(&mut self, key: Key) -> Result<(), Self::Error>;
+    fn release(&mut self, key: Key) -> Result<(), Self::Error>;
+    fn sync(&mut self) -> Result<(), Self::Error>;
+}
+
+/// Where a gesture button currently sits in its press/release/re-press
+/// timeline, between the moment it first went down and the moment its
+/// gesture (tap, hold, or double-tap) is resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PendingGesture {
+    /// Down since `since_ms`, not yet released and not yet held past
+    /// [`HOLD_THRESHOLD_MS`].
+    Holding { since_ms: u64 },
+    /// Released (a tap candidate) at `since_ms`; waiting to see whether a
+    /// second press arrives before [`DOUBLE_TAP_WINDOW_MS`] closes.
+    AwaitingSecondTap { since_ms: u64 },
+}
+
+/// Ties button/layer/gesture config, modifier tracking, and the
+/// scheduled-release queue together into the single remap decision point
+/// both backends share. Driven by feeding it every input event plus,
+/// independently, a periodic `now_ms` tick so it can flush due releases and
+/// resolve timed-out gestures.
+pub struct RemapEngine<B: VirtualKeyboard> {
+    backend: B,
+    mapping: ButtonMap,
+    layers: LayerMap,
+    gestures: GestureMap,
+    modifier_state: ModifierState,
+    layer_manager: LayerManager,
+    queue: VecDeque<ScheduledEvent>,
+    pending_gestures: BTreeMap<Key, PendingGesture>,
+}
+
+impl<B: VirtualKeyboard> RemapEngine<B> {
+    pub fn new(backend: B, mapping: ButtonMap, layers: LayerMap, gestures: GestureMap) -> Self {
+        Self {
+            backend,
+            mapping,
+            layers,
+            gestures,
+            modifier_state: ModifierState::default(),
+            layer_manager: LayerManager::default(),
+            queue: VecDeque::new(),
+            pending_gestures: BTreeMap::new(),
+        }
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Every key code this engine may ever emit, so a caller can register
+    /// virtual-device key capabilities up front.
+    pub fn emitted_keys(&self) -> Vec<Key> {
+        emitted_keys(&self.mapping, &self.layers, &self.gestures)
+    }
+
+    /// Update physical modifier state from a key event observed on a device
+    /// other than the one [`Self::handle_event`] is driven from -- the
+    /// remapped source device is usually a mouse (or a single gesture/layer
+    /// button), which never reports Shift/Ctrl/Alt/Meta itself, so a caller
+    /// that wants [`ModifierState`] to reflect reality has to also watch a
+    /// real keyboard and feed its modifier events through here instead.
+    /// Non-modifier keys are ignored; autorepeat (`value == 2`) is a no-op,
+    /// matching the same handling in `handle_event`.
+    pub fn note_modifier(&mut self, key: Key, value: i32) {
+        if key.is_modifier() && value != 2 {
+            self.modifier_state.set(key, value == 1);
+        }
+    }
+
+    /// Feed a single key event (`code`, evdev-style `value`: 1 press, 0
+    /// release, 2 autorepeat) at time `now_ms`.
+    pub fn handle_event(&mut self, code: Key, value: i32, now_ms: u64) -> Result<Disposition, B::Error> {
+        if code.is_modifier() && value != 2 {
+            self.modifier_state.set(code, value == 1);
+        }
+
+        if self.gestures.contains_key(&code) {
+            match value {
+                1 => self.on_gesture_press(code, now_ms)?,
+                0 => self.on_gesture_release(code, now_ms),
+                _ => {}
+            }
+            return Ok(Disposition::Consumed);
+        }
+
+        if self.layers.contains_key(&code) {
+            match value {
+                1 => self.layer_manager.push(code),
+                0 => self.release_layer(code, now_ms)?,
+                _ => {}
+            }
+            return Ok(Disposition::Consumed);
+        }
+
+        let active_layer = self.layer_manager.active();
+        let chord = active_layer
+            .and_then(|layer| self.layers.get(&layer))
+            .and_then(|table| table.get(&code))
+            .or_else(|| self.mapping.get(&code))
+            .cloned();
+
+        let Some(chord) = chord else {
+            return Ok(Disposition::PassThrough);
+        };
+
+        // The physical button is fully remapped: its own press/release
+        // never reaches the caller, even on release (value 0), which the
+        // chord's own scheduled release already accounts for.
+        if value == 1 {
+            self.send_chord(&chord, active_layer, now_ms)?;
+        }
+        Ok(Disposition::Consumed)
+    }
+
+    /// Flush every scheduled action whose deadline has elapsed: key
+    /// transitions fire directly, gesture deadlines are re-checked since
+    /// they may have been superseded by events since they were scheduled.
+    pub fn drain(&mut self, now_ms: u64) -> Result<(), B::Error> {
+        let mut due = Vec::new();
+        let mut remaining = VecDeque::new();
+        while let Some(pending) = self.queue.pop_front() {
+            if pending.emit_at <= now_ms {
+                due.push(pending);
+            } else {
+                remaining.push_back(pending);
+            }
+        }
+        due.sort_by_key(|e| e.emit_at);
+        self.queue = remaining;
+
+        let mut emitted = false;
+        for pending in due {
+            match pending.action {
+                ScheduledAction::Emit(KeyTransition::Press(key)) => {
+                    self.backend.press(key)?;
+                    emitted = true;
+                }
+                ScheduledAction::Emit(KeyTransition::Release(key)) => {
+                    self.backend.release(key)?;
+                    emitted = true;
+                }
+                ScheduledAction::GestureDeadline(code) => self.resolve_gesture_deadline(code, now_ms)?,
+            }
+        }
+        if emitted {
+            self.backend.sync()?;
+        }
+        Ok(())
+    }
+
+    /// The deadline of the next scheduled action, if any -- a caller
+    /// driving its own timer/poll loop uses this to know when to next call
+    /// [`Self::drain`].
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.queue.iter().map(|e| e.emit_at).min()
+    }
+
+    fn send_chord(&mut self, chord: &[Key], layer: Option<Key>, now_ms: u64) -> Result<(), B::Error> {
+        let mut sent = Vec::new();
+        for &key in chord {
+            if key.is_modifier() && self.modifier_state.is_held(key) {
+                continue;
+            }
+            self.backend.press(key)?;
+            sent.push(key);
+        }
+        self.backend.sync()?;
+
+        let emit_at = now_ms + CHORD_RELEASE_DELAY_MS;
+        for &key in sent.iter().rev() {
+            self.queue.push_back(ScheduledEvent {
+                action: ScheduledAction::Emit(KeyTransition::Release(key)),
+                emit_at,
+                layer,
+            });
+        }
+        Ok(())
+    }
+
+    /// Pop `layer_button` off the active-layer stack and immediately emit
+    /// the release for every key still scheduled because of that layer, so
+    /// closing a layer can never leave one of its chords' keys stuck down.
+    fn release_layer(&mut self, layer_button: Key, _now_ms: u64) -> Result<(), B::Error> {
+        self.layer_manager.pop(layer_button);
+
+        let mut remaining = VecDeque::new();
+        let mut released = false;
+        while let Some(pending) = self.queue.pop_front() {
+            if pending.layer == Some(layer_button) {
+                if let ScheduledAction::Emit(KeyTransition::Release(key)) = pending.action {
+                    self.backend.release(key)?;
+                    released = true;
+                }
+            } else {
+                remaining.push_back(pending);
+            }
+        }
+        self.queue = remaining;
+
+        if released {
+            self.backend.sync()?;
+        }
+        Ok(())
+    }
+
+    /// A gesture button went down. If a tap is already waiting on a
+    /// possible second press (see [`Self::on_gesture_release`]), this is
+    /// that second press: resolve as a double-tap immediately, consuming it
+    /// entirely (its eventual release is just a no-op, handled below since
+    /// `pending_gestures` no longer has an entry for it). Otherwise this is
+    /// a fresh press: start timing it against the hold threshold.
+    fn on_gesture_press(&mut self, code: Key, now_ms: u64) -> Result<(), B::Error> {
+        if matches!(self.pending_gestures.get(&code), Some(PendingGesture::AwaitingSecondTap { .. })) {
+            self.pending_gestures.remove(&code);
+            return self.fire_gesture(code, |c| c.double_tap.as_deref(), now_ms);
+        }
+
+        self.pending_gestures.insert(code, PendingGesture::Holding { since_ms: now_ms });
+        self.queue.push_back(ScheduledEvent {
+            action: ScheduledAction::GestureDeadline(code),
+            emit_at: now_ms + HOLD_THRESHOLD_MS,
+            layer: None,
+        });
+        Ok(())
+    }
+
+    /// A gesture button came back up. If it was still in `Holding` state,
+    /// the hold threshold never fired, so this is a tap candidate -- defer
+    /// it behind the double-tap window instead of firing immediately. Any
+    /// other state (already resolved as a hold, or nothing pending) means
+    /// this gesture already fired or was cancelled; the release itself
+    /// carries no further action, which is what makes "a hold that's
+    /// released can't also fire its tap" hold without special-casing it.
+    fn on_gesture_release(&mut self, code: Key, now_ms: u64) {
+        if matches!(self.pending_gestures.get(&code), Some(PendingGesture::Holding { .. })) {
+            self.pending_gestures.insert(code, PendingGesture::AwaitingSecondTap { since_ms: now_ms });
+            self.queue.push_back(ScheduledEvent {
+                action: ScheduledAction::GestureDeadline(code),
+                emit_at: now_ms + DOUBLE_TAP_WINDOW_MS,
+                layer: None,
+            });
+        }
+    }
+
+    /// A previously scheduled [`ScheduledAction::GestureDeadline`] came due.
+    /// Re-check `code`'s current state rather than assuming it's still the
+    /// same gesture that scheduled this deadline -- a second tap or an
+    /// out-of-order release may have already resolved it.
+    fn resolve_gesture_deadline(&mut self, code: Key, now_ms: u64) -> Result<(), B::Error> {
+        match self.pending_gestures.get(&code).copied() {
+            Some(PendingGesture::Holding { since_ms }) if now_ms.saturating_sub(since_ms) >= HOLD_THRESHOLD_MS => {
+                self.pending_gestures.remove(&code);
+                self.fire_gesture(code, |c| c.hold.as_deref(), now_ms)?;
+            }
+            Some(PendingGesture::AwaitingSecondTap { since_ms })
+                if now_ms.saturating_sub(since_ms) >= DOUBLE_TAP_WINDOW_MS =>
+            {
+                self.pending_gestures.remove(&code);
+                self.fire_gesture(code, |c| c.tap.as_deref(), now_ms)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn fire_gesture(
+        &mut self,
+        code: Key,
+        select: impl FnOnce(&GestureChords) -> Option<&[Key]>,
+        now_ms: u64,
+    ) -> Result<(), B::Error> {
+        let Some(chord) = self.gestures.get(&code).and_then(select).map(<[Key]>::to_vec) else {
+            return Ok(());
+        };
+        let layer = self.layer_manager.active();
+        self.send_chord(&chord, layer, now_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records every press/release/sync `RemapEngine` makes, in order, so
+    /// tests can assert on them without any real device.
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<KeyTransition>,
+        syncs: usize,
+    }
+
+    impl VirtualKeyboard for Recorder {
+        type Error = core::convert::Infallible;
+
+        fn press(&mut self, key: Key) -> Result<(), Self::Error> {
+            self.events.push(KeyTransition::Press(key));
+            Ok(())
+        }
+
+        fn release(&mut self, key: Key) -> Result<(), Self::Error> {
+            self.events.push(KeyTransition::Release(key));
+            Ok(())
+        }
+
+        fn sync(&mut self) -> Result<(), Self::Error> {
+            self.syncs += 1;
+            Ok(())
+        }
+    }
+
+    fn gesture_engine(chords: GestureChords) -> RemapEngine<Recorder> {
+        let mut gestures = GestureMap::new();
+        gestures.insert(Key::ButtonMiddle, chords);
+        RemapEngine::new(Recorder::default(), ButtonMap::new(), LayerMap::new(), gestures)
+    }
+
+    #[test]
+    fn gesture_tap_fires_after_double_tap_window_with_no_second_press() {
+        let mut engine = gesture_engine(GestureChords {
+            tap: Some(alloc::vec![Key::LeftMeta]),
+            hold: Some(alloc::vec![Key::LeftMeta, Key::PageUp]),
+            double_tap: Some(alloc::vec![Key::LeftMeta, Key::PageDown]),
+        });
+
+        engine.handle_event(Key::ButtonMiddle, 1, 0).unwrap();
+        engine.handle_event(Key::ButtonMiddle, 0, 10).unwrap();
+
+        // Released well before the hold threshold, so this is a tap
+        // candidate: nothing should fire until the double-tap window closes
+        // at 10 + DOUBLE_TAP_WINDOW_MS.
+        engine.drain(10 + DOUBLE_TAP_WINDOW_MS - 1).unwrap();
+        assert!(engine.backend_mut().events.is_empty());
+
+        engine.drain(10 + DOUBLE_TAP_WINDOW_MS).unwrap();
+        assert_eq!(engine.backend_mut().events, alloc::vec![KeyTransition::Press(Key::LeftMeta)]);
+    }
+
+    #[test]
+    fn gesture_double_tap_fires_immediately_on_second_press() {
+        let mut engine = gesture_engine(GestureChords {
+            tap: Some(alloc::vec![Key::LeftMeta]),
+            hold: None,
+            double_tap: Some(alloc::vec![Key::LeftMeta, Key::PageDown]),
+        });
+
+        engine.handle_event(Key::ButtonMiddle, 1, 0).unwrap();
+        engine.handle_event(Key::ButtonMiddle, 0, 50).unwrap();
+        engine.handle_event(Key::ButtonMiddle, 1, 100).unwrap();
+
+        assert_eq!(
+            engine.backend_mut().events,
+            alloc::vec![KeyTransition::Press(Key::LeftMeta), KeyTransition::Press(Key::PageDown)]
+        );
+
+        // The stale tap deadline from the first press must not also fire --
+        // a resolved double-tap means there's no pending gesture left, so
+        // the only thing left to drain is the double-tap chord's own
+        // scheduled release.
+        engine.drain(50 + DOUBLE_TAP_WINDOW_MS).unwrap();
+        assert_eq!(
+            engine.backend_mut().events,
+            alloc::vec![
+                KeyTransition::Press(Key::LeftMeta),
+                KeyTransition::Press(Key::PageDown),
+                KeyTransition::Release(Key::PageDown),
+                KeyTransition::Release(Key::LeftMeta),
+            ]
+        );
+    }
+
+    #[test]
+    fn gesture_hold_fires_once_threshold_elapses() {
+        let mut engine = gesture_engine(GestureChords {
+            tap: Some(alloc::vec![Key::LeftMeta]),
+            hold: Some(alloc::vec![Key::LeftMeta, Key::PageUp]),
+            double_tap: None,
+        });
+
+        engine.handle_event(Key::ButtonMiddle, 1, 0).unwrap();
+
+        // Still holding, well short of the threshold: nothing fires yet.
+        engine.drain(HOLD_THRESHOLD_MS - 1).unwrap();
+        assert!(engine.backend_mut().events.is_empty());
+
+        engine.drain(HOLD_THRESHOLD_MS).unwrap();
+        assert_eq!(
+            engine.backend_mut().events,
+            alloc::vec![KeyTransition::Press(Key::LeftMeta), KeyTransition::Press(Key::PageUp)]
+        );
+
+        // Releasing after the hold already fired must not also fire the tap
+        // -- only the hold chord's own scheduled release shows up.
+        engine.handle_event(Key::ButtonMiddle, 0, HOLD_THRESHOLD_MS + 10).unwrap();
+        engine.drain(HOLD_THRESHOLD_MS + 10 + DOUBLE_TAP_WINDOW_MS).unwrap();
+        assert_eq!(
+            engine.backend_mut().events,
+            alloc::vec![
+                KeyTransition::Press(Key::LeftMeta),
+                KeyTransition::Press(Key::PageUp),
+                KeyTransition::Release(Key::PageUp),
+                KeyTransition::Release(Key::LeftMeta),
+            ]
+        );
+    }
+
+    #[test]
+    fn gesture_released_just_before_hold_threshold_becomes_tap_not_hold() {
+        let mut engine = gesture_engine(GestureChords {
+            tap: Some(alloc::vec![Key::LeftMeta]),
+            hold: Some(alloc::vec![Key::LeftMeta, Key::PageUp]),
+            double_tap: None,
+        });
+
+        engine.handle_event(Key::ButtonMiddle, 1, 0).unwrap();
+        engine.handle_event(Key::ButtonMiddle, 0, HOLD_THRESHOLD_MS - 1).unwrap();
+
+        // The hold deadline scheduled at t=0 is still in the queue and comes
+        // due at exactly HOLD_THRESHOLD_MS, but the button is no longer
+        // `Holding` (it's now `AwaitingSecondTap`), so this is the stale
+        // re-check that must be a no-op rather than firing the hold chord.
+        engine.drain(HOLD_THRESHOLD_MS).unwrap();
+        assert!(engine.backend_mut().events.is_empty());
+
+        engine.drain(HOLD_THRESHOLD_MS - 1 + DOUBLE_TAP_WINDOW_MS).unwrap();
+        assert_eq!(engine.backend_mut().events, alloc::vec![KeyTransition::Press(Key::LeftMeta)]);
+    }
+
+    #[test]
+    fn chord_press_schedules_matching_release_at_release_delay() {
+        let mut mapping = ButtonMap::new();
+        mapping.insert(Key::ButtonSide, alloc::vec![Key::LeftMeta, Key::PageDown]);
+        let mut engine =
+            RemapEngine::new(Recorder::default(), mapping, LayerMap::new(), GestureMap::new());
+
+        engine.handle_event(Key::ButtonSide, 1, 0).unwrap();
+
+        // The chord's presses land immediately, synced once, with nothing
+        // scheduled to fire before CHORD_RELEASE_DELAY_MS.
+        assert_eq!(
+            engine.backend_mut().events,
+            alloc::vec![KeyTransition::Press(Key::LeftMeta), KeyTransition::Press(Key::PageDown)]
+        );
+        assert_eq!(engine.backend_mut().syncs, 1);
+        assert_eq!(engine.next_deadline(), Some(CHORD_RELEASE_DELAY_MS));
+
+        engine.drain(CHORD_RELEASE_DELAY_MS - 1).unwrap();
+        assert_eq!(engine.backend_mut().events.len(), 2);
+
+        engine.drain(CHORD_RELEASE_DELAY_MS).unwrap();
+        assert_eq!(
+            engine.backend_mut().events,
+            alloc::vec![
+                KeyTransition::Press(Key::LeftMeta),
+                KeyTransition::Press(Key::PageDown),
+                KeyTransition::Release(Key::PageDown),
+                KeyTransition::Release(Key::LeftMeta),
+            ]
+        );
+        assert_eq!(engine.backend_mut().syncs, 2);
+    }
+
+    #[test]
+    fn chord_does_not_press_or_release_a_modifier_the_user_is_already_holding() {
+        let mut mapping = ButtonMap::new();
+        mapping.insert(Key::ButtonSide, alloc::vec![Key::LeftMeta, Key::PageDown]);
+        let mut engine =
+            RemapEngine::new(Recorder::default(), mapping, LayerMap::new(), GestureMap::new());
+
+        // The user is physically holding LeftMeta already (observed via a
+        // real keyboard, not the remapped source device).
+        engine.note_modifier(Key::LeftMeta, 1);
+
+        engine.handle_event(Key::ButtonSide, 1, 0).unwrap();
+        engine.drain(CHORD_RELEASE_DELAY_MS).unwrap();
+
+        // Only PageDown is pressed/released -- LeftMeta must never appear in
+        // the recorder at all, so the physical hold is left exactly as the
+        // user has it afterward.
+        assert_eq!(
+            engine.backend_mut().events,
+            alloc::vec![KeyTransition::Press(Key::PageDown), KeyTransition::Release(Key::PageDown)]
+        );
+    }
+
+    #[test]
+    fn release_layer_flushes_its_scheduled_releases_immediately() {
+        let mut table = ButtonMap::new();
+        table.insert(Key::ButtonMiddle, alloc::vec![Key::Up]);
+        let mut layers = LayerMap::new();
+        layers.insert(Key::LeftCtrl, table);
+        let mut engine =
+            RemapEngine::new(Recorder::default(), ButtonMap::new(), layers, GestureMap::new());
+
+        engine.handle_event(Key::LeftCtrl, 1, 0).unwrap();
+        engine.handle_event(Key::ButtonMiddle, 1, 0).unwrap();
+        assert_eq!(engine.backend_mut().events, alloc::vec![KeyTransition::Press(Key::Up)]);
+
+        // The chord release isn't due for CHORD_RELEASE_DELAY_MS yet, but
+        // releasing the layer button that owns it must flush it right away
+        // -- a layer can never leave one of its chords' keys stuck down.
+        engine.handle_event(Key::LeftCtrl, 0, 1).unwrap();
+        assert_eq!(
+            engine.backend_mut().events,
+            alloc::vec![KeyTransition::Press(Key::Up), KeyTransition::Release(Key::Up)]
+        );
+
+        // Nothing left scheduled for it, so draining past the original
+        // deadline must not emit a second release.
+        engine.drain(CHORD_RELEASE_DELAY_MS + 1).unwrap();
+        assert_eq!(
+            engine.backend_mut().events,
+            alloc::vec![KeyTransition::Press(Key::Up), KeyTransition::Release(Key::Up)]
+        );
+    }
+
+    #[test]
+    fn parse_button_map_builds_chord_per_entry() {
+        let mapping = parse_button_map("BTN_SIDE=LEFTMETA+PAGEDOWN,BTN_EXTRA=LEFTMETA+PAGEUP").unwrap();
+        assert_eq!(mapping.get(&Key::ButtonSide), Some(&alloc::vec![Key::LeftMeta, Key::PageDown]));
+        assert_eq!(mapping.get(&Key::ButtonExtra), Some(&alloc::vec![Key::LeftMeta, Key::PageUp]));
+    }
+
+    #[test]
+    fn parse_button_map_rejects_entry_with_no_equals() {
+        assert_eq!(
+            parse_button_map("BTN_SIDE-LEFTMETA"),
+            Err(ParseError::MalformedEntry(Buf::from("BTN_SIDE-LEFTMETA")))
+        );
+    }
+
+    #[test]
+    fn parse_button_map_rejects_unknown_key_name() {
+        assert_eq!(
+            parse_button_map("BTN_SIDE=NOTAKEY"),
+            Err(ParseError::UnknownKeyName(Buf::from("NOTAKEY")))
+        );
+    }
+
+    #[test]
+    fn parse_button_map_rejects_empty_chord() {
+        // `"BTN_SIDE="` splits to a single empty key name, which is rejected
+        // as an unknown key before `parse_chord`'s own empty-chord check is
+        // ever reached -- `split('+')` never yields zero items.
+        assert_eq!(parse_button_map("BTN_SIDE=").unwrap_err(), ParseError::UnknownKeyName(Buf::from("")));
+    }
+
+    #[test]
+    fn parse_button_map_ignores_blank_entries() {
+        let mapping = parse_button_map(",BTN_SIDE=LEFTMETA,,").unwrap();
+        assert_eq!(mapping.len(), 1);
+    }
+
+    #[test]
+    fn parse_layer_map_merges_entries_sharing_a_layer_button() {
+        let layers = parse_layer_map("LEFTCTRL:BTN_SIDE=UP,LEFTCTRL:BTN_EXTRA=DOWN").unwrap();
+        let table = layers.get(&Key::LeftCtrl).unwrap();
+        assert_eq!(table.get(&Key::ButtonSide), Some(&alloc::vec![Key::Up]));
+        assert_eq!(table.get(&Key::ButtonExtra), Some(&alloc::vec![Key::Down]));
+    }
+
+    #[test]
+    fn parse_layer_map_rejects_entry_with_no_layer_separator() {
+        assert_eq!(
+            parse_layer_map("LEFTCTRL-BTN_SIDE=UP"),
+            Err(ParseError::MalformedEntry(Buf::from("LEFTCTRL-BTN_SIDE=UP")))
+        );
+    }
+
+    #[test]
+    fn parse_gesture_map_merges_tap_hold_double_for_one_button() {
+        let gestures = parse_gesture_map(
+            "BTN_MIDDLE/TAP=LEFTMETA+UP,BTN_MIDDLE/HOLD=LEFTMETA+DOWN,BTN_MIDDLE/DOUBLE=LEFTMETA+LEFT",
+        )
+        .unwrap();
+        let chords = gestures.get(&Key::ButtonMiddle).unwrap();
+        assert_eq!(chords.tap, Some(alloc::vec![Key::LeftMeta, Key::Up]));
+        assert_eq!(chords.hold, Some(alloc::vec![Key::LeftMeta, Key::Down]));
+        assert_eq!(chords.double_tap, Some(alloc::vec![Key::LeftMeta, Key::Left]));
+    }
+
+    #[test]
+    fn parse_gesture_map_rejects_unknown_gesture_kind() {
+        assert_eq!(
+            parse_gesture_map("BTN_MIDDLE/TRIPLE=LEFTMETA+UP").unwrap_err(),
+            ParseError::MalformedEntry(Buf::from("BTN_MIDDLE/TRIPLE=LEFTMETA+UP"))
+        );
+    }
+
+    #[test]
+    fn parse_gesture_map_rejects_entry_with_no_gesture_separator() {
+        assert_eq!(
+            parse_gesture_map("BTN_MIDDLE-TAP=LEFTMETA+UP").unwrap_err(),
+            ParseError::MalformedEntry(Buf::from("BTN_MIDDLE-TAP=LEFTMETA+UP"))
+        );
+    }
+
+    #[test]
+    fn emitted_keys_dedupes_and_sorts_across_mapping_layers_and_gestures() {
+        let mapping = parse_button_map("BTN_SIDE=LEFTMETA+PAGEDOWN").unwrap();
+        let layers = parse_layer_map("LEFTCTRL:BTN_EXTRA=LEFTMETA+PAGEUP").unwrap();
+        let gestures = parse_gesture_map("BTN_MIDDLE/TAP=LEFTMETA+UP").unwrap();
+
+        let keys = emitted_keys(&mapping, &layers, &gestures);
+
+        assert_eq!(keys.iter().filter(|k| **k == Key::LeftMeta).count(), 1);
+        assert!(keys.contains(&Key::PageDown));
+        assert!(keys.contains(&Key::PageUp));
+        assert!(keys.contains(&Key::Up));
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+    }
+
+    #[test]
+    fn parse_device_selector_empty_means_no_pin() {
+        assert_eq!(parse_device_selector("").unwrap(), None);
+        assert_eq!(parse_device_selector("   ").unwrap(), None);
+    }
+
+    #[test]
+    fn parse_device_selector_parses_hex_vendor_product() {
+        assert_eq!(
+            parse_device_selector("046d:b01c").unwrap(),
+            Some(DeviceSelector::VendorProduct { vendor: 0x046d, product: 0xb01c })
+        );
+    }
+
+    #[test]
+    fn parse_device_selector_treats_by_path_string_as_a_path_not_vendor_product() {
+        // Contains colons (from the PCI address segment), which must not be
+        // mistaken for the VVVV:PPPP form -- neither half parses as hex, so
+        // this falls through to a verbatim Path match.
+        let path = "/dev/input/by-path/pci-0000:00:14.0-usb-0:1:1.0-event-mouse";
+        assert_eq!(
+            parse_device_selector(path).unwrap(),
+            Some(DeviceSelector::Path(String::from(path)))
+        );
+    }
+
+    #[test]
+    fn parse_device_selector_treats_by_id_string_with_no_colon_as_a_path() {
+        let path = "/dev/input/by-id/usb-Logitech_M720-event-mouse";
+        assert_eq!(
+            parse_device_selector(path).unwrap(),
+            Some(DeviceSelector::Path(String::from(path)))
+        );
+    }
+}
@@ -0,0 +1,93 @@
+//! The userspace [`remap_core::VirtualKeyboard`] implementation: a uinput
+//! virtual device that the remap engine presses/releases keys on.
+
+use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+use evdev::{AttributeSet, EventType, InputEvent, Key as EvKey};
+use remap_core::Key;
+use std::io;
+
+/// Map our backend-agnostic [`Key`] onto the real evdev key code uinput
+/// expects. Kept as one small translation table so adding a key to
+/// `remap-core` only ever needs one matching line here.
+fn to_evdev(key: Key) -> EvKey {
+    match key {
+        Key::LeftShift => EvKey::KEY_LEFTSHIFT,
+        Key::RightShift => EvKey::KEY_RIGHTSHIFT,
+        Key::LeftCtrl => EvKey::KEY_LEFTCTRL,
+        Key::RightCtrl => EvKey::KEY_RIGHTCTRL,
+        Key::LeftAlt => EvKey::KEY_LEFTALT,
+        Key::RightAlt => EvKey::KEY_RIGHTALT,
+        Key::LeftMeta => EvKey::KEY_LEFTMETA,
+        Key::RightMeta => EvKey::KEY_RIGHTMETA,
+        Key::PageUp => EvKey::KEY_PAGEUP,
+        Key::PageDown => EvKey::KEY_PAGEDOWN,
+        Key::Left => EvKey::KEY_LEFT,
+        Key::Right => EvKey::KEY_RIGHT,
+        Key::Up => EvKey::KEY_UP,
+        Key::Down => EvKey::KEY_DOWN,
+        // The source buttons never get emitted on the virtual device, only
+        // looked up as mapping keys, but the table stays total so adding a
+        // new source button can't silently forget this match.
+        Key::ButtonSide => EvKey::BTN_SIDE,
+        Key::ButtonExtra => EvKey::BTN_EXTRA,
+        Key::ButtonMiddle => EvKey::BTN_MIDDLE,
+    }
+}
+
+/// Builds a uinput keyboard exposing exactly the keys the engine might ever
+/// emit, then presses/releases/syncs it on request.
+pub struct UinputBackend {
+    device: VirtualDevice,
+    /// Key events queued by `press`/`release` since the last `sync`. Holding
+    /// these back lets a chord's modifier+key presses reach the device as
+    /// one batch with a single trailing SYN_REPORT, matching the engine's
+    /// press-all-then-sync contract instead of one report per key.
+    pending: Vec<InputEvent>,
+}
+
+impl UinputBackend {
+    pub fn new(name: &str, emitted_keys: &[Key]) -> io::Result<Self> {
+        let mut keys = AttributeSet::<EvKey>::new();
+        for &key in emitted_keys {
+            keys.insert(to_evdev(key));
+        }
+
+        let device = VirtualDeviceBuilder::new()?
+            .name(name)
+            .with_keys(&keys)?
+            .build()?;
+
+        Ok(Self {
+            device,
+            pending: Vec::new(),
+        })
+    }
+
+    fn emit_key(&mut self, key: Key, value: i32) {
+        self.pending
+            .push(InputEvent::new(EventType::KEY, to_evdev(key).code(), value));
+    }
+}
+
+impl remap_core::VirtualKeyboard for UinputBackend {
+    type Error = io::Error;
+
+    fn press(&mut self, key: Key) -> io::Result<()> {
+        self.emit_key(key, 1);
+        Ok(())
+    }
+
+    fn release(&mut self, key: Key) -> io::Result<()> {
+        self.emit_key(key, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.device.emit(&self.pending)?;
+        self.pending.clear();
+        Ok(())
+    }
+}
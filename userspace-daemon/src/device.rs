@@ -0,0 +1,160 @@
+//! Finding and opening the M720 exclusively under `/dev/input`.
+
+use evdev::{Device, Key as EvKey};
+use remap_core::{DeviceSelector, Key};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The inverse of `backend::to_evdev`: translate a physical evdev key code
+/// into `remap-core`'s backend-agnostic [`Key`]. Returns `None` for codes
+/// `remap-core` doesn't know about, which the read loop treats as an
+/// ordinary pass-through key.
+pub fn from_evdev_key(key: EvKey) -> Option<Key> {
+    Some(match key {
+        EvKey::KEY_LEFTSHIFT => Key::LeftShift,
+        EvKey::KEY_RIGHTSHIFT => Key::RightShift,
+        EvKey::KEY_LEFTCTRL => Key::LeftCtrl,
+        EvKey::KEY_RIGHTCTRL => Key::RightCtrl,
+        EvKey::KEY_LEFTALT => Key::LeftAlt,
+        EvKey::KEY_RIGHTALT => Key::RightAlt,
+        EvKey::KEY_LEFTMETA => Key::LeftMeta,
+        EvKey::KEY_RIGHTMETA => Key::RightMeta,
+        EvKey::KEY_PAGEUP => Key::PageUp,
+        EvKey::KEY_PAGEDOWN => Key::PageDown,
+        EvKey::KEY_LEFT => Key::Left,
+        EvKey::KEY_RIGHT => Key::Right,
+        EvKey::KEY_UP => Key::Up,
+        EvKey::KEY_DOWN => Key::Down,
+        EvKey::BTN_SIDE => Key::ButtonSide,
+        EvKey::BTN_EXTRA => Key::ButtonExtra,
+        EvKey::BTN_MIDDLE => Key::ButtonMiddle,
+        _ => return None,
+    })
+}
+
+/// Mirrors `M720Remapper::is_m720_device` in the kernel module: a name
+/// substring match plus the capability check that rules out e.g. a
+/// Logitech keyboard that happens to also match on name.
+pub fn is_m720_device(dev: &Device) -> bool {
+    let name_matches = dev
+        .name()
+        .map(|n| n.contains("M720") || n.contains("Logitech"))
+        .unwrap_or(false);
+
+    name_matches
+        && dev
+            .supported_keys()
+            .map(|keys| keys.contains(EvKey::BTN_SIDE) && keys.contains(EvKey::BTN_EXTRA))
+            .unwrap_or(false)
+}
+
+/// Open the target device: pinned deterministically by `selector` if one
+/// was configured, falling back to the name/capability heuristic in
+/// [`is_m720_device`] otherwise.
+pub fn open_device(selector: Option<&DeviceSelector>) -> io::Result<Device> {
+    match selector {
+        Some(DeviceSelector::VendorProduct { vendor, product }) => {
+            open_by_vendor_product(*vendor, *product)
+        }
+        Some(DeviceSelector::Path(path)) => open_by_path(path),
+        None => open_m720(),
+    }
+}
+
+/// Open every keyboard-capable device under `/dev/input` *without* grabbing
+/// it, so the remap engine's [`remap_core::RemapEngine::note_modifier`] can
+/// see real Shift/Ctrl/Alt/Meta presses -- the M720 itself is a mouse and
+/// never reports them, so `modifier_state` would otherwise sit empty
+/// forever. The M720 is never returned here: [`is_keyboard_device`]'s
+/// capability check already excludes it, since a mouse has none of these
+/// keys, so there's no need to also exclude it by path.
+pub fn open_keyboards() -> io::Result<Vec<Device>> {
+    let mut keyboards = Vec::new();
+    for entry in fs::read_dir("/dev/input")? {
+        let path = entry?.path();
+        if !is_event_node(&path) {
+            continue;
+        }
+        if let Ok(dev) = Device::open(&path) {
+            if is_keyboard_device(&dev) {
+                keyboards.push(dev);
+            }
+        }
+    }
+    Ok(keyboards)
+}
+
+/// True for a device that reports at least one physical modifier key --
+/// good enough to tell a real keyboard apart from the M720 (which has none
+/// of these) without needing a name match.
+fn is_keyboard_device(dev: &Device) -> bool {
+    dev.supported_keys()
+        .map(|keys| {
+            keys.contains(EvKey::KEY_LEFTSHIFT)
+                || keys.contains(EvKey::KEY_LEFTCTRL)
+                || keys.contains(EvKey::KEY_LEFTALT)
+                || keys.contains(EvKey::KEY_LEFTMETA)
+        })
+        .unwrap_or(false)
+}
+
+/// Scan `/dev/input/event*` and open the first device that looks like an
+/// M720, grabbing it exclusively so its raw button events stop reaching
+/// anything else (the whole point of the remap: we re-emit them ourselves
+/// on the virtual keyboard).
+pub fn open_m720() -> io::Result<Device> {
+    for entry in fs::read_dir("/dev/input")? {
+        let path = entry?.path();
+        if !is_event_node(&path) {
+            continue;
+        }
+        if let Ok(mut dev) = Device::open(&path) {
+            if is_m720_device(&dev) {
+                dev.grab()?;
+                return Ok(dev);
+            }
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, "no M720 device found under /dev/input"))
+}
+
+/// Open the device at `path` -- a `/dev/input/by-id/...` or
+/// `/dev/input/by-path/...` symlink, or a direct event node -- exactly as
+/// given, skipping the name/capability heuristic entirely.
+fn open_by_path(path: &str) -> io::Result<Device> {
+    let target = fs::canonicalize(path)?;
+    let mut dev = Device::open(&target)?;
+    dev.grab()?;
+    Ok(dev)
+}
+
+/// Scan `/dev/input/event*` for the device whose USB vendor:product id
+/// matches, regardless of name -- the right call when several similarly
+/// named Logitech devices are present (e.g. behind one Unifying receiver).
+fn open_by_vendor_product(vendor: u16, product: u16) -> io::Result<Device> {
+    for entry in fs::read_dir("/dev/input")? {
+        let path = entry?.path();
+        if !is_event_node(&path) {
+            continue;
+        }
+        if let Ok(mut dev) = Device::open(&path) {
+            let id = dev.input_id();
+            if id.vendor() == vendor && id.product() == product {
+                dev.grab()?;
+                return Ok(dev);
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no device with vendor:product {vendor:04x}:{product:04x} found under /dev/input"),
+    ))
+}
+
+fn is_event_node(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.starts_with("event"))
+        .unwrap_or(false)
+}
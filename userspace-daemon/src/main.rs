@@ -0,0 +1,161 @@
+//! Userspace evdev/uinput fallback daemon for the M720 remapper.
+//!
+//! Real Rust-for-Linux kernel APIs aren't available yet (see
+//! `kernel-module/rust-implementation`), so this runs the same remap logic
+//! entirely in userspace: grab the M720 via `/dev/input/eventX`, create a
+//! uinput virtual keyboard, and drive `remap_core::RemapEngine` from a plain
+//! read loop. Run as root (or with the right udev rules for `/dev/uinput`
+//! and the M720's event node).
+
+mod backend;
+mod device;
+
+use backend::UinputBackend;
+use evdev::InputEventKind;
+use remap_core::RemapEngine;
+use std::env;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+const BUTTON_MAP_DEFAULT: &str = "BTN_SIDE=LEFTMETA+PAGEDOWN,BTN_EXTRA=LEFTMETA+PAGEUP";
+const LAYER_MAP_DEFAULT: &str = "";
+
+/// `SRC/GESTURE=MOD1+...+KEY` comma-separated triples, `GESTURE` one of
+/// `TAP`/`HOLD`/`DOUBLE`, e.g. `"BTN_MIDDLE/TAP=LEFTMETA+UP,BTN_MIDDLE/HOLD=LEFTMETA+DOWN"`.
+/// Empty by default, i.e. no gesture buttons.
+const GESTURE_MAP_DEFAULT: &str = "";
+
+/// `VVVV:PPPP` vendor:product id, or a `/dev/input/by-id/...` /
+/// `/dev/input/by-path/...` path; empty (the default) falls back to the
+/// name/capability heuristic in `device::is_m720_device`.
+const DEVICE_SELECTOR_DEFAULT: &str = "";
+
+/// How often the drain loop checks for a due scheduled action (a chord
+/// release or a gesture hold/double-tap deadline). Well under
+/// `remap_core::CHORD_RELEASE_DELAY_MS` so releases land close to their
+/// deadline without busy-waiting.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+fn main() -> io::Result<()> {
+    let button_map = env::var("M720_BUTTON_MAP").unwrap_or_else(|_| BUTTON_MAP_DEFAULT.into());
+    let layer_map = env::var("M720_LAYER_MAP").unwrap_or_else(|_| LAYER_MAP_DEFAULT.into());
+    let gesture_map = env::var("M720_GESTURE_MAP").unwrap_or_else(|_| GESTURE_MAP_DEFAULT.into());
+    let device_selector =
+        env::var("M720_DEVICE_SELECTOR").unwrap_or_else(|_| DEVICE_SELECTOR_DEFAULT.into());
+
+    let mapping = remap_core::parse_button_map(&button_map)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let layers = remap_core::parse_layer_map(&layer_map)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let gestures = remap_core::parse_gesture_map(&gesture_map)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let device_selector = remap_core::parse_device_selector(&device_selector)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+
+    let mut source = device::open_device(device_selector.as_ref())?;
+    println!(
+        "Grabbed M720 at {}",
+        source.physical_path().unwrap_or("<unknown>")
+    );
+
+    // The M720 is a mouse: exclusively grabbing it means the engine never
+    // sees a real Shift/Ctrl/Alt/Meta press from it. Watch every keyboard
+    // under /dev/input unexclusively too, purely to feed modifier state into
+    // the engine, so a remapped chord never corrupts a modifier the user is
+    // already physically holding.
+    let mut keyboards = device::open_keyboards()?;
+    println!("Watching {} keyboard device(s) for modifier state", keyboards.len());
+
+    let emitted_keys = remap_core::emitted_keys(&mapping, &layers, &gestures);
+    let backend = UinputBackend::new("M720 Virtual Keyboard", &emitted_keys)?;
+    let engine = RemapEngine::new(backend, mapping, layers, gestures);
+
+    run(&mut source, &mut keyboards, engine)
+}
+
+fn run(
+    source: &mut evdev::Device,
+    keyboards: &mut [evdev::Device],
+    mut engine: RemapEngine<UinputBackend>,
+) -> io::Result<()> {
+    let start = Instant::now();
+    let now_ms = || start.elapsed().as_millis() as u64;
+
+    let mut pollfds: Vec<libc::pollfd> = core::iter::once(source.as_raw_fd())
+        .chain(keyboards.iter().map(|kbd| kbd.as_raw_fd()))
+        .map(|fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 })
+        .collect();
+
+    loop {
+        // Block only up to `DRAIN_POLL_INTERVAL`, not until the next input
+        // event: that's what lets `drain` run on schedule even while every
+        // watched device sits idle, so a chord release or gesture deadline
+        // lands on time instead of waiting for whatever event happens to
+        // arrive next.
+        poll_readable(&mut pollfds, DRAIN_POLL_INTERVAL)?;
+
+        if pollfds[0].revents & libc::POLLIN != 0 {
+            for event in source.fetch_events()? {
+                if let InputEventKind::Key(code) = event.kind() {
+                    if let Some(core_key) = device::from_evdev_key(code) {
+                        engine.handle_event(core_key, event.value(), now_ms())?;
+                        continue;
+                    }
+                }
+                // Not a key this engine remaps (mouse movement, scroll,
+                // unmapped buttons, ...). Forwarding that passthrough traffic
+                // from an exclusively-grabbed device would require mirroring
+                // the rest of the device's capabilities onto a second virtual
+                // device, which is out of scope here.
+            }
+        }
+
+        for (kbd, pfd) in keyboards.iter_mut().zip(&pollfds[1..]) {
+            if pfd.revents & libc::POLLIN == 0 {
+                continue;
+            }
+            for event in kbd.fetch_events()? {
+                if let InputEventKind::Key(code) = event.kind() {
+                    if let Some(core_key) = device::from_evdev_key(code) {
+                        // Not grabbed, so the OS already delivers this event
+                        // to its normal destination; we only need to mirror
+                        // modifier presses into the engine's own tracking.
+                        engine.note_modifier(core_key, event.value());
+                    }
+                }
+            }
+        }
+
+        if let Err(e) = engine.drain(now_ms()) {
+            eprintln!("Failed to emit scheduled key event: {e}");
+        }
+    }
+}
+
+/// Wait up to `timeout` for any fd in `pollfds` to have input ready. Lets the
+/// read loop fall through to `drain` on every tick instead of blocking until
+/// the next event on whichever device is slowest to produce one.
+///
+/// Retries on `EINTR`: `poll(2)` returns that whenever any signal is
+/// delivered to the process (SIGWINCH, SIGCHLD from unrelated activity,
+/// ...), and a long-running daemon must not treat an ordinary signal as a
+/// fatal error.
+fn poll_readable(pollfds: &mut [libc::pollfd], timeout: Duration) -> io::Result<()> {
+    let timeout_ms = timeout.as_millis() as libc::c_int;
+    loop {
+        for pfd in pollfds.iter_mut() {
+            pfd.revents = 0;
+        }
+        let ready =
+            unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout_ms) };
+        if ready < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        return Ok(());
+    }
+}